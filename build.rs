@@ -0,0 +1,19 @@
+use std::env;
+use std::process::Command;
+
+// Lets `tests/fn_traits.rs` gate itself on `#[cfg(hackfn_nightly_tests)]`,
+// since the real `Fn`/`FnMut`/`FnOnce` impls it exercises require nightly's
+// `fn_traits`/`unboxed_closures` features.
+fn main() {
+    println!("cargo::rustc-check-cfg=cfg(hackfn_nightly_tests)");
+
+    let rustc = env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+    let version = Command::new(rustc).arg("--version").output();
+    let is_nightly = version
+        .map(|output| String::from_utf8_lossy(&output.stdout).contains("nightly"))
+        .unwrap_or(false);
+
+    if is_nightly {
+        println!("cargo:rustc-cfg=hackfn_nightly_tests");
+    }
+}
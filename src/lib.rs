@@ -13,13 +13,35 @@
 //!
 //! # Limitations
 //!
-//! - The function must receive `&self`. Functions that receive `&mut self` or
-//!   `self` are not supported.
+//! - By default the function must receive `&self` or `&mut self`. Functions
+//!   that receive `self` by value are only supported in `fn_traits` mode, see
+//!   below.
 //!
 //! - The function may not have generic parameters or where-clause.
 //!
 //! - The `Self` type must implement `Sized`.
 //!
+//! # Soundness
+//!
+//! The default expansion works by faking a `Fn`/`FnMut` impl through `Deref`,
+//! which relies on the unstated assumption that a particular move closure has
+//! the same layout as `Self`; this is checked with a runtime `assert_eq!` on
+//! the two types' `Layout`, but is not guaranteed by the language.
+//!
+//! Passing `fn_traits` as an argument to the attribute, i.e.
+//! `#[hackfn(fn_traits)]`, selects an alternative expansion that implements
+//! the real `Fn`/`FnMut`/`FnOnce` traits instead of relying on the `Deref`
+//! hack. This requires a nightly compiler with:
+//!
+//! ```ignore
+//! #![feature(fn_traits, unboxed_closures)]
+//! ```
+//!
+//! enabled in the crate containing the `#[hackfn(fn_traits)]` attribute.
+//! This mode is also the only way to use a function that takes `self` by
+//! value, since that can be expressed as `FnOnce` but has no representation
+//! through `Deref`.
+//!
 //! # Examples
 //!
 //! ```
@@ -44,7 +66,8 @@
 //!
 //! The next example is somewhat more elaborate:
 //!
-//! - Interior mutability can be used to approximate a `FnMut` impl.
+//! - `&mut self` is supported, so the function can mutate fields directly
+//!   rather than going through interior mutability.
 //!
 //! - Generic parameters and where-clause are permitted on the impl block
 //!   (though not on the function).
@@ -54,31 +77,55 @@
 //! ```
 //! use hackfn::hackfn;
 //!
-//! use std::cell::Cell;
 //! use std::ops::Add;
 //!
 //! /// Function object that accumulates a pair of values per call.
 //! #[derive(Default)]
 //! struct AccumulatePairs<T> {
-//!     first: Cell<T>,
-//!     second: Cell<T>,
+//!     first: T,
+//!     second: T,
 //! }
 //!
 //! #[hackfn]
 //! impl<T> AccumulatePairs<T> where T: Copy + Add<Output = T> {
-//!     fn call(&self, first: T, second: T) {
-//!         self.first.set(self.first.get() + first);
-//!         self.second.set(self.second.get() + second);
+//!     fn call(&mut self, first: T, second: T) {
+//!         self.first = self.first + first;
+//!         self.second = self.second + second;
 //!     }
 //! }
 //!
 //! fn main() {
-//!     let accumulate = AccumulatePairs::default();
+//!     let mut accumulate = AccumulatePairs::default();
 //!     accumulate(30, 1);
 //!     accumulate(20, 2);
 //!     accumulate(10, 3);
-//!     assert_eq!(accumulate.first.get(), 60);
-//!     assert_eq!(accumulate.second.get(), 6);
+//!     assert_eq!(accumulate.first, 60);
+//!     assert_eq!(accumulate.second, 6);
+//! }
+//! ```
+//!
+//! On nightly, `#[hackfn(fn_traits)]` implements the real `Fn`/`FnMut`/
+//! `FnOnce` traits, which also makes it possible to take `self` by value:
+//!
+//! ```ignore
+//! #![feature(fn_traits, unboxed_closures)]
+//!
+//! use hackfn::hackfn;
+//!
+//! /// Function object that adds some number to its input, once.
+//! struct PlusOnce(u32);
+//!
+//! #[hackfn(fn_traits)]
+//! impl PlusOnce {
+//!     fn call(self, other: u32) -> u32 {
+//!         self.0 + other
+//!     }
+//! }
+//!
+//! fn main() {
+//!     let plus_one = PlusOnce(1);
+//!     let sum = plus_one(2);
+//!     assert_eq!(sum, 3);
 //! }
 //! ```
 
@@ -90,9 +137,10 @@ extern crate proc_macro;
 use proc_macro::TokenStream;
 use proc_macro2::TokenStream as TokenStream2;
 use quote::quote;
-use syn::parse::{Nothing, Parse, ParseStream, Result};
+use syn::parse::{Parse, ParseStream, Result};
 use syn::{
-    braced, parenthesized, parse_macro_input, Attribute, Generics, Ident, Token, Type, Visibility,
+    braced, parenthesized, parse_macro_input, Attribute, Error, Generics, Ident, Token, Type,
+    Visibility,
 };
 
 struct FnArg {
@@ -109,6 +157,41 @@ impl Parse for FnArg {
     }
 }
 
+// What mode the `#[hackfn]`/`#[hackfn(fn_traits)]` attribute argument selects.
+enum Mode {
+    // Default: fake a `Fn`/`FnMut` impl through a `Deref`/`DerefMut` hack.
+    Transmute,
+    // `#[hackfn(fn_traits)]`: implement the real `Fn`/`FnMut`/`FnOnce` traits,
+    // nightly only.
+    FnTraits,
+}
+
+impl Parse for Mode {
+    fn parse(input: ParseStream) -> Result<Self> {
+        if input.is_empty() {
+            return Ok(Mode::Transmute);
+        }
+        let ident: Ident = input.parse()?;
+        if ident == "fn_traits" {
+            Ok(Mode::FnTraits)
+        } else {
+            Err(Error::new(
+                ident.span(),
+                "unrecognized hackfn argument, expected `fn_traits`",
+            ))
+        }
+    }
+}
+
+// The kind of `self` receiver accepted by the annotated function. `Value`
+// carries whether `self` was written as `mut self`, which only affects the
+// mutability of the generated method's own parameter.
+enum Receiver {
+    Ref,
+    RefMut,
+    Value { mutable: bool },
+}
+
 struct HackFn {
     impl_attrs: Vec<Attribute>,
     generics: Generics,
@@ -116,6 +199,7 @@ struct HackFn {
     fn_attrs: Vec<Attribute>,
     vis: Visibility,
     method: Ident,
+    receiver: Receiver,
     args: Vec<FnArg>,
     ret_ty: Option<Type>,
     body: TokenStream2,
@@ -139,7 +223,17 @@ impl Parse for HackFn {
 
         let argument_list;
         parenthesized!(argument_list in impl_block);
-        argument_list.parse::<Token![&]>()?;
+        let receiver = if argument_list.peek(Token![&]) {
+            argument_list.parse::<Token![&]>()?;
+            if argument_list.parse::<Option<Token![mut]>>()?.is_some() {
+                Receiver::RefMut
+            } else {
+                Receiver::Ref
+            }
+        } else {
+            let mutable = argument_list.parse::<Option<Token![mut]>>()?.is_some();
+            Receiver::Value { mutable }
+        };
         argument_list.parse::<Token![self]>()?;
 
         let mut args = Vec::new();
@@ -168,6 +262,7 @@ impl Parse for HackFn {
             fn_attrs,
             vis,
             method,
+            receiver,
             args,
             ret_ty,
             body,
@@ -177,7 +272,7 @@ impl Parse for HackFn {
 
 #[proc_macro_attribute]
 pub fn hackfn(args: TokenStream, input: TokenStream) -> TokenStream {
-    parse_macro_input!(args as Nothing);
+    let mode = parse_macro_input!(args as Mode);
 
     let HackFn {
         impl_attrs,
@@ -186,53 +281,212 @@ pub fn hackfn(args: TokenStream, input: TokenStream) -> TokenStream {
         fn_attrs,
         vis,
         method,
+        receiver,
         args,
         ret_ty,
         body,
     } = parse_macro_input!(input as HackFn);
 
+    if let (Mode::Transmute, Receiver::Value { .. }) = (&mode, &receiver) {
+        let message = "functions that receive `self` by value require `#[hackfn(fn_traits)]`";
+        return TokenStream::from(Error::new(method.span(), message).to_compile_error());
+    }
+
     let impl_attrs = &impl_attrs;
     let where_clause = &generics.where_clause;
     let arg_names = args.iter().map(|fn_arg| &fn_arg.ident).collect::<Vec<_>>();
     let arg_types = args.iter().map(|fn_arg| &fn_arg.ty).collect::<Vec<_>>();
-    let ret_ty = ret_ty.map(|ret| quote!(-> #ret));
+    let output_ty = ret_ty.as_ref().map(|ty| quote!(#ty)).unwrap_or(quote!(()));
+    let ret_arrow = ret_ty.map(|ty| quote!(-> #ty));
 
-    let target = quote! {
-        dyn ::std::ops::Fn(#(#arg_types),*) #ret_ty
+    let self_param = match receiver {
+        Receiver::Ref => quote!(&self),
+        Receiver::RefMut => quote!(&mut self),
+        Receiver::Value { mutable: true } => quote!(mut self),
+        Receiver::Value { mutable: false } => quote!(self),
     };
 
-    let expanded = quote! {
+    let method_impl = quote! {
         #(#impl_attrs)*
         impl #generics #self_ty #where_clause {
             #(#fn_attrs)*
-            #vis fn #method(&self #(, #arg_names: #arg_types)*) #ret_ty {
+            #vis fn #method(#self_param #(, #arg_names: #arg_types)*) #ret_arrow {
                 #body
             }
         }
+    };
 
-        #(#impl_attrs)*
-        impl #generics ::std::ops::Deref for #self_ty #where_clause {
-            type Target = #target;
-
-            // This implementation assumes that a closure that captures a type T
-            // by move has the same layout as T.
-            #[allow(clippy::forget_non_drop, clippy::transmute_ptr_to_ptr)]
-            fn deref(&self) -> &Self::Target {
-                let __this = ::std::mem::MaybeUninit::<Self>::uninit();
-                let __closure = move |#(#arg_names : #arg_types),*| #ret_ty {
-                    Self::#method(
-                        unsafe { &*__this.as_ptr() }
-                        #(, #arg_names)*
-                    )
+    let expanded = match mode {
+        Mode::Transmute => match receiver {
+            Receiver::Ref => {
+                let target = quote! {
+                    dyn ::std::ops::Fn(#(#arg_types),*) #ret_arrow
                 };
-                let __layout_of_closure = ::std::alloc::Layout::for_value(&__closure);
-                fn __second<'__a, __T>(__first: &__T, __second: &'__a __T) -> &'__a __T {
-                    __second
+
+                quote! {
+                    #method_impl
+
+                    #(#impl_attrs)*
+                    impl #generics ::std::ops::Deref for #self_ty #where_clause {
+                        type Target = #target;
+
+                        // This implementation assumes that a closure that captures a type T
+                        // by move has the same layout as T.
+                        #[allow(clippy::forget_non_drop, clippy::transmute_ptr_to_ptr)]
+                        fn deref(&self) -> &Self::Target {
+                            let __this = ::std::mem::MaybeUninit::<Self>::uninit();
+                            let __closure = move |#(#arg_names : #arg_types),*| #ret_arrow {
+                                Self::#method(
+                                    unsafe { &*__this.as_ptr() }
+                                    #(, #arg_names)*
+                                )
+                            };
+                            let __layout_of_closure = ::std::alloc::Layout::for_value(&__closure);
+                            fn __second<'__a, __T>(__first: &__T, __second: &'__a __T) -> &'__a __T {
+                                __second
+                            }
+                            let __ret = __second(&__closure, unsafe { &*(self as *const Self as *const _) });
+                            ::std::mem::forget(__closure);
+                            assert_eq!(__layout_of_closure, ::std::alloc::Layout::new::<Self>());
+                            unsafe { ::std::mem::transmute(__ret as &#target) }
+                        }
+                    }
+                }
+            }
+
+            Receiver::RefMut => {
+                let target = quote! {
+                    dyn ::std::ops::FnMut(#(#arg_types),*) #ret_arrow
+                };
+
+                quote! {
+                    #method_impl
+
+                    #(#impl_attrs)*
+                    impl #generics ::std::ops::Deref for #self_ty #where_clause {
+                        type Target = #target;
+
+                        // This implementation assumes that a closure that captures a type T
+                        // by move has the same layout as T.
+                        #[allow(clippy::forget_non_drop, clippy::transmute_ptr_to_ptr)]
+                        fn deref(&self) -> &Self::Target {
+                            let __this = ::std::mem::MaybeUninit::<Self>::uninit();
+                            let __closure = move |#(#arg_names : #arg_types),*| #ret_arrow {
+                                Self::#method(
+                                    unsafe { &mut *(__this.as_ptr() as *mut Self) }
+                                    #(, #arg_names)*
+                                )
+                            };
+                            let __layout_of_closure = ::std::alloc::Layout::for_value(&__closure);
+                            fn __second<'__a, __T>(__first: &__T, __second: &'__a __T) -> &'__a __T {
+                                __second
+                            }
+                            let __ret = __second(&__closure, unsafe { &*(self as *const Self as *const _) });
+                            ::std::mem::forget(__closure);
+                            assert_eq!(__layout_of_closure, ::std::alloc::Layout::new::<Self>());
+                            unsafe { ::std::mem::transmute(__ret as &#target) }
+                        }
+                    }
+
+                    #(#impl_attrs)*
+                    impl #generics ::std::ops::DerefMut for #self_ty #where_clause {
+                        // This implementation assumes that a closure that captures a type T
+                        // by move has the same layout as T.
+                        #[allow(clippy::forget_non_drop, clippy::transmute_ptr_to_ptr)]
+                        fn deref_mut(&mut self) -> &mut Self::Target {
+                            let __this = ::std::mem::MaybeUninit::<Self>::uninit();
+                            let mut __closure = move |#(#arg_names : #arg_types),*| #ret_arrow {
+                                Self::#method(
+                                    unsafe { &mut *(__this.as_ptr() as *mut Self) }
+                                    #(, #arg_names)*
+                                )
+                            };
+                            let __layout_of_closure = ::std::alloc::Layout::for_value(&__closure);
+                            fn __second<'__a, __T>(__first: &mut __T, __second: &'__a mut __T) -> &'__a mut __T {
+                                __second
+                            }
+                            let __ret = __second(&mut __closure, unsafe { &mut *(self as *mut Self as *mut _) });
+                            ::std::mem::forget(__closure);
+                            assert_eq!(__layout_of_closure, ::std::alloc::Layout::new::<Self>());
+                            unsafe { ::std::mem::transmute(__ret as &mut #target) }
+                        }
+                    }
                 }
-                let __ret = __second(&__closure, unsafe { &*(self as *const Self as *const _) });
-                ::std::mem::forget(__closure);
-                assert_eq!(__layout_of_closure, ::std::alloc::Layout::new::<Self>());
-                unsafe { ::std::mem::transmute(__ret as &#target) }
+            }
+
+            Receiver::Value { .. } => {
+                unreachable!("rejected above: by-value self requires fn_traits mode")
+            }
+        },
+
+        // Sound expansion: implement the real `Fn`/`FnMut`/`FnOnce` traits
+        // instead of relying on the `Deref` transmute hack. Requires
+        // `#![feature(fn_traits, unboxed_closures)]` on nightly.
+        Mode::FnTraits => {
+            let arg_types_tuple = quote! {
+                (#(#arg_types,)*)
+            };
+
+            match receiver {
+                Receiver::Ref => quote! {
+                    #method_impl
+
+                    #(#impl_attrs)*
+                    impl #generics ::std::ops::Fn<#arg_types_tuple> for #self_ty #where_clause {
+                        extern "rust-call" fn call(&self, (#(#arg_names,)*): #arg_types_tuple) -> Self::Output {
+                            Self::#method(self #(, #arg_names)*)
+                        }
+                    }
+
+                    #(#impl_attrs)*
+                    impl #generics ::std::ops::FnMut<#arg_types_tuple> for #self_ty #where_clause {
+                        extern "rust-call" fn call_mut(&mut self, args: #arg_types_tuple) -> Self::Output {
+                            ::std::ops::Fn::call(self, args)
+                        }
+                    }
+
+                    #(#impl_attrs)*
+                    impl #generics ::std::ops::FnOnce<#arg_types_tuple> for #self_ty #where_clause {
+                        type Output = #output_ty;
+
+                        extern "rust-call" fn call_once(self, args: #arg_types_tuple) -> Self::Output {
+                            ::std::ops::Fn::call(&self, args)
+                        }
+                    }
+                },
+
+                Receiver::RefMut => quote! {
+                    #method_impl
+
+                    #(#impl_attrs)*
+                    impl #generics ::std::ops::FnMut<#arg_types_tuple> for #self_ty #where_clause {
+                        extern "rust-call" fn call_mut(&mut self, (#(#arg_names,)*): #arg_types_tuple) -> Self::Output {
+                            Self::#method(self #(, #arg_names)*)
+                        }
+                    }
+
+                    #(#impl_attrs)*
+                    impl #generics ::std::ops::FnOnce<#arg_types_tuple> for #self_ty #where_clause {
+                        type Output = #output_ty;
+
+                        extern "rust-call" fn call_once(mut self, args: #arg_types_tuple) -> Self::Output {
+                            ::std::ops::FnMut::call_mut(&mut self, args)
+                        }
+                    }
+                },
+
+                Receiver::Value { .. } => quote! {
+                    #method_impl
+
+                    #(#impl_attrs)*
+                    impl #generics ::std::ops::FnOnce<#arg_types_tuple> for #self_ty #where_clause {
+                        type Output = #output_ty;
+
+                        extern "rust-call" fn call_once(self, (#(#arg_names,)*): #arg_types_tuple) -> Self::Output {
+                            Self::#method(self #(, #arg_names)*)
+                        }
+                    }
+                },
             }
         }
     };
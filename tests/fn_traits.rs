@@ -0,0 +1,84 @@
+// `#[hackfn(fn_traits)]` expands to real `Fn`/`FnMut`/`FnOnce` impls, which
+// requires a nightly compiler with `#![feature(fn_traits, unboxed_closures)]`
+// enabled. `hackfn_nightly_tests` is set by build.rs when it detects a
+// nightly `rustc`, so this file compiles to nothing (and is skipped) on
+// stable, while actually running on a nightly toolchain.
+#![cfg(hackfn_nightly_tests)]
+#![feature(fn_traits, unboxed_closures)]
+
+mod first {
+    use hackfn::hackfn;
+
+    /// Function object that adds some number to its input.
+    struct Plus(u32);
+
+    #[hackfn(fn_traits)]
+    impl Plus {
+        fn call(&self, other: u32) -> u32 {
+            self.0 + other
+        }
+    }
+
+    #[test]
+    fn main() {
+        let plus_one = Plus(1);
+        let sum = plus_one(2);
+        assert_eq!(sum, 3);
+    }
+}
+
+mod second {
+    use hackfn::hackfn;
+
+    use std::ops::Add;
+
+    /// Function object that accumulates a pair of values per call.
+    #[derive(Default)]
+    struct AccumulatePairs<T> {
+        first: T,
+        second: T,
+    }
+
+    #[hackfn(fn_traits)]
+    impl<T> AccumulatePairs<T>
+    where
+        T: Copy + Add<Output = T>,
+    {
+        fn call(&mut self, first: T, second: T) {
+            self.first = self.first + first;
+            self.second = self.second + second;
+        }
+    }
+
+    #[test]
+    fn main() {
+        let mut accumulate = AccumulatePairs::default();
+        accumulate(30, 1);
+        accumulate(20, 2);
+        accumulate(10, 3);
+        assert_eq!(accumulate.first, 60);
+        assert_eq!(accumulate.second, 6);
+    }
+}
+
+mod third {
+    use hackfn::hackfn;
+
+    /// Function object that adds some number to its input, once.
+    struct PlusOnce(u32);
+
+    #[hackfn(fn_traits)]
+    impl PlusOnce {
+        fn call(mut self, other: u32) -> u32 {
+            self.0 += other;
+            self.0
+        }
+    }
+
+    #[test]
+    fn main() {
+        let plus_one = PlusOnce(1);
+        let sum = plus_one(2);
+        assert_eq!(sum, 3);
+    }
+}
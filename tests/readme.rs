@@ -33,7 +33,10 @@ mod second {
     }
 
     #[hackfn]
-    impl<T> AccumulatePairs<T> where T: Copy + Add<Output = T> {
+    impl<T> AccumulatePairs<T>
+    where
+        T: Copy + Add<Output = T>,
+    {
         fn call(&self, first: T, second: T) {
             self.first.set(self.first.get() + first);
             self.second.set(self.second.get() + second);
@@ -50,3 +53,37 @@ mod second {
         assert_eq!(accumulate.second.get(), 6);
     }
 }
+
+mod third {
+    use hackfn::hackfn;
+
+    use std::ops::Add;
+
+    /// Function object that accumulates a pair of values per call.
+    #[derive(Default)]
+    struct AccumulatePairs<T> {
+        first: T,
+        second: T,
+    }
+
+    #[hackfn]
+    impl<T> AccumulatePairs<T>
+    where
+        T: Copy + Add<Output = T>,
+    {
+        fn call(&mut self, first: T, second: T) {
+            self.first = self.first + first;
+            self.second = self.second + second;
+        }
+    }
+
+    #[test]
+    fn main() {
+        let mut accumulate = AccumulatePairs::default();
+        accumulate(30, 1);
+        accumulate(20, 2);
+        accumulate(10, 3);
+        assert_eq!(accumulate.first, 60);
+        assert_eq!(accumulate.second, 6);
+    }
+}